@@ -1,14 +1,28 @@
 use crate::{db::UserId, Executor, Result};
 use crate::{Database, Error};
-use anyhow::anyhow;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use dashmap::DashMap;
+use moka::future::Cache;
 use parking_lot::Mutex;
 use sea_orm::prelude::DateTimeUtc;
 use std::any::TypeId;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use thiserror::Error as ThisError;
 use util::ResultExt;
 
+/// How long a bucket can sit idle in `RateLimiter::buckets` before it's evicted. This
+/// bounds the cache's memory growth without an explicit sweep: a bucket that hasn't
+/// been touched in this long is cheap to recreate (or reload from the database) the
+/// next time its user makes a request.
+const BUCKET_CACHE_TIME_TO_IDLE: StdDuration = StdDuration::from_secs(60 * 30);
+
+/// How often buffered bucket state is flushed to the database. A hot user's bucket
+/// only gets written once per interval, no matter how many checks they make.
+const DEFAULT_SAVE_FLUSH_INTERVAL: StdDuration = StdDuration::from_secs(30);
+
 pub trait RateLimit: 'static {
     fn capacity() -> usize;
     fn refill_duration() -> Duration;
@@ -16,105 +30,469 @@ pub trait RateLimit: 'static {
     fn type_id() -> TypeId {
         TypeId::of::<Self>()
     }
+
+    /// The capacity of the optional byte-budget bucket for this limit, in bytes.
+    /// Limits that only care about request counts can leave this as `None`.
+    fn bytes_capacity() -> Option<usize> {
+        None
+    }
+
+    /// How long it takes the byte-budget bucket to refill from empty to full.
+    /// Must be `Some` if and only if `bytes_capacity` is `Some`.
+    fn bytes_refill_duration() -> Option<Duration> {
+        None
+    }
 }
 
 /// Used to enforce per-user rate limits
 pub struct RateLimiter {
-    buckets: DashMap<(UserId, TypeId), Arc<Mutex<RateBucket>>>,
+    buckets: Cache<(UserId, TypeId), Arc<Mutex<RateBucket>>>,
     db: Arc<Database>,
     executor: Executor,
+    metrics: RateLimiterMetrics,
+    pending_saves: Arc<DashMap<(UserId, &'static str), PendingSave>>,
 }
 
 impl RateLimiter {
     pub fn new(db: Arc<Database>, executor: Executor) -> Self {
+        Self::new_with_save_flush_interval(db, executor, DEFAULT_SAVE_FLUSH_INTERVAL)
+    }
+
+    fn new_with_save_flush_interval(
+        db: Arc<Database>,
+        executor: Executor,
+        flush_interval: StdDuration,
+    ) -> Self {
+        let buckets = Cache::builder()
+            .time_to_idle(BUCKET_CACHE_TIME_TO_IDLE)
+            .build();
+        let pending_saves = Arc::new(DashMap::new());
+
+        executor.spawn_detached({
+            let pending_saves = pending_saves.clone();
+            let db = db.clone();
+            let executor = executor.clone();
+            async move {
+                loop {
+                    executor.sleep(flush_interval).await;
+                    flush_pending_saves(&pending_saves, &db).await;
+                }
+            }
+        });
+
         RateLimiter {
-            buckets: DashMap::new(),
+            buckets,
             db,
             executor,
+            metrics: RateLimiterMetrics::default(),
+            pending_saves,
         }
     }
 
-    /// Returns an error if the user has exceeded the specified `RateLimit`.
-    /// Attempts to read the from the database if no cached RateBucket currently exists.
-    pub async fn check<T: RateLimit>(&self, user_id: UserId) -> Result<()> {
+    /// Returns a snapshot of the rejection counters and approximate distinct
+    /// throttled-user counts, suitable for scraping by a metrics endpoint.
+    pub fn metrics_snapshot(&self) -> RateLimiterMetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Immediately persists any buffered bucket state rather than waiting for the next
+    /// scheduled flush. Callers should invoke this during graceful shutdown so recent
+    /// rate-limit activity isn't lost.
+    pub async fn flush_pending_saves(&self) {
+        flush_pending_saves(&self.pending_saves, &self.db).await;
+    }
+
+    /// Returns `Err(RateLimitExceeded)` if the user has exceeded the specified
+    /// `RateLimit`. Attempts to read the from the database if no cached RateBucket
+    /// currently exists.
+    pub async fn check<T: RateLimit>(&self, user_id: UserId) -> Result<(), RateLimitExceeded> {
         self.check_internal::<T>(user_id, Utc::now()).await
     }
 
-    async fn check_internal<T: RateLimit>(&self, user_id: UserId, now: DateTimeUtc) -> Result<()> {
+    /// Like `check`, but additionally charges `ops` against the ops bucket (instead of
+    /// the usual single token) and `bytes` against the limit's byte-budget bucket, if
+    /// it declares one. Use this for requests whose cost varies with size, such as
+    /// streaming LLM completions that should count for more than one ops token.
+    pub async fn check_with_cost<T: RateLimit>(
+        &self,
+        user_id: UserId,
+        ops: usize,
+        bytes: usize,
+    ) -> Result<(), RateLimitExceeded> {
+        self.check_internal_with_cost::<T>(user_id, ops, bytes, Utc::now())
+            .await
+    }
+
+    async fn check_internal<T: RateLimit>(
+        &self,
+        user_id: UserId,
+        now: DateTimeUtc,
+    ) -> Result<(), RateLimitExceeded> {
+        self.check_internal_with_cost::<T>(user_id, 1, 0, now).await
+    }
+
+    async fn check_internal_with_cost<T: RateLimit>(
+        &self,
+        user_id: UserId,
+        ops: usize,
+        bytes: usize,
+        now: DateTimeUtc,
+    ) -> Result<(), RateLimitExceeded> {
         let type_id = T::type_id();
         let bucket_key = (user_id, type_id);
 
-        // Attempt to fetch the bucket from the database if it hasn't been cached.
-        // For now, we keep buckets in memory for the lifetime of the process rather than expiring them,
-        // but this enforces limits across restarts so long as the database is reachable.
-        if !self.buckets.contains_key(&bucket_key) {
-            if let Some(bucket) = self.load_bucket::<T>(user_id).await.log_err().flatten() {
-                self.buckets
-                    .insert(bucket_key, Arc::new(Mutex::new(bucket)));
-            }
-        }
-
+        // Fetch the cached bucket, or atomically load it from the database (falling
+        // back to a fresh bucket) if this is the first check for this key. Concurrent
+        // callers for the same key coalesce onto a single load, so none of them can
+        // clobber another's freshly-loaded state.
+        let db = self.db.clone();
         let bucket = self
             .buckets
-            .entry(bucket_key)
-            .or_insert_with(|| {
-                Arc::new(Mutex::new(RateBucket::new(
-                    T::capacity(),
-                    T::refill_duration(),
-                    now,
-                )))
+            .get_with(bucket_key, async move {
+                let loaded = load_bucket::<T>(&db, user_id).await.log_err().flatten();
+                Arc::new(Mutex::new(loaded.unwrap_or_else(|| {
+                    RateBucket::new(
+                        T::capacity(),
+                        T::refill_duration(),
+                        T::bytes_capacity(),
+                        T::bytes_refill_duration(),
+                        now,
+                    )
+                })))
             })
-            .value()
-            .clone();
+            .await;
 
         let mut lock = bucket.lock();
-        let allowed = lock.allow(now);
-        let token_count = lock.token_count;
-        let last_refill = lock.last_refill.naive_utc();
+        let allowed = lock.allow(now, ops, bytes);
+        let token_count = lock.ops.token_count;
+        let bytes_token_count = lock.bytes.as_ref().map(|bucket| bucket.token_count);
+        let last_refill = lock.ops.last_refill.naive_utc();
+        // The bytes bucket ticks on its own `refill_time_per_token`, independent of
+        // the ops bucket's, so its `last_refill` is tracked and persisted separately
+        // rather than reusing the ops bucket's timestamp.
+        let bytes_last_refill = lock
+            .bytes
+            .as_ref()
+            .map(|bucket| bucket.last_refill.naive_utc());
         drop(lock);
 
-        // Perform a non-blocking save of the rate bucket to the database in its new state.
-        let db = self.db.clone();
-        self.executor.spawn_detached(async move {
-            db.save_rate_bucket(user_id, T::db_name(), token_count as i32, last_refill)
-                .await
-                .log_err();
-        });
+        // Buffer this bucket's new state for the next periodic flush, keeping only the
+        // latest state per key so a hot user's bucket is written at most once per
+        // flush interval instead of once per check.
+        self.pending_saves.insert(
+            (user_id, T::db_name()),
+            PendingSave {
+                token_count: token_count as i32,
+                bytes_token_count: bytes_token_count.map(|count| count as i32),
+                last_refill,
+                bytes_last_refill,
+            },
+        );
 
-        if !allowed {
-            Err(anyhow!("rate limit exceeded"))?
+        self.metrics.record_check();
+        match allowed {
+            AllowResult::Allowed => Ok(()),
+            AllowResult::Throttled { retry_after } => {
+                self.metrics.record_rejection(T::db_name(), user_id);
+                Err(RateLimitExceeded { retry_after })
+            }
         }
-
-        Ok(())
     }
+}
 
-    async fn load_bucket<K: RateLimit>(
-        &self,
-        user_id: UserId,
-    ) -> Result<Option<RateBucket>, Error> {
-        Ok(self
-            .db
-            .get_rate_bucket(user_id, K::db_name())
-            .await?
-            .map(|saved_bucket| RateBucket {
+async fn load_bucket<K: RateLimit>(
+    db: &Database,
+    user_id: UserId,
+) -> Result<Option<RateBucket>, Error> {
+    Ok(db
+        .get_rate_bucket(user_id, K::db_name())
+        .await?
+        .map(|saved_bucket| RateBucket {
+            ops: TokenBucket {
                 capacity: K::capacity(),
-                refill_time_per_token: K::refill_duration(),
+                refill_time_per_token: K::refill_duration() / K::capacity() as i32,
                 token_count: saved_bucket.token_count as usize,
                 last_refill: DateTime::from_naive_utc_and_offset(saved_bucket.last_refill, Utc),
-            }))
+            },
+            bytes: K::bytes_capacity().map(|capacity| TokenBucket {
+                capacity,
+                refill_time_per_token: K::bytes_refill_duration()
+                    .expect("bytes_refill_duration must be set when bytes_capacity is")
+                    / capacity as i32,
+                // Fall back to a full bucket if this is the first time the limit has
+                // had a byte budget, or the row predates one (`bytes_token_count` is
+                // `NULL`) — never if it was actually persisted as partially drained.
+                token_count: saved_bucket
+                    .bytes_token_count
+                    .map_or(capacity, |count| count as usize)
+                    .min(capacity),
+                // Likewise fall back to the ops bucket's `last_refill` (the best
+                // available reference point) if this row predates the bytes bucket
+                // having its own persisted timestamp.
+                last_refill: DateTime::from_naive_utc_and_offset(
+                    saved_bucket.bytes_last_refill.unwrap_or(saved_bucket.last_refill),
+                    Utc,
+                ),
+            }),
+        }))
+}
+
+/// The most recent bucket state for a `(UserId, db_name)` pair, awaiting persistence.
+#[derive(Clone, Copy)]
+struct PendingSave {
+    token_count: i32,
+    /// `None` for limits that don't declare a byte-budget bucket.
+    bytes_token_count: Option<i32>,
+    last_refill: NaiveDateTime,
+    /// The bytes bucket's own `last_refill`, tracked separately from the ops
+    /// bucket's since the two tick at different rates and would otherwise drift
+    /// apart. `None` for limits that don't declare a byte-budget bucket.
+    bytes_last_refill: Option<NaiveDateTime>,
+}
+
+/// Drains `pending_saves` and writes each entry's state to the database exactly once.
+/// Entries inserted after a key is drained are left for the next flush, so a write
+/// never overwrites a database row with state that's now stale.
+async fn flush_pending_saves(
+    pending_saves: &DashMap<(UserId, &'static str), PendingSave>,
+    db: &Database,
+) {
+    let keys: Vec<_> = pending_saves.iter().map(|entry| *entry.key()).collect();
+    for (user_id, db_name) in keys {
+        let Some((_, pending_save)) = pending_saves.remove(&(user_id, db_name)) else {
+            continue;
+        };
+        db.save_rate_bucket(
+            user_id,
+            db_name,
+            pending_save.token_count,
+            pending_save.bytes_token_count,
+            pending_save.last_refill,
+            pending_save.bytes_last_refill,
+        )
+        .await
+        .log_err();
     }
 }
 
+/// The number of registers (and thus memory) the per-`db_name` `Hll` uses to estimate
+/// distinct throttled users. Higher precision trades memory for accuracy; 14 bits
+/// (16384 registers) keeps relative error around 1% while staying tiny per `db_name`.
+const HLL_PRECISION: u32 = 14;
+const HLL_NUM_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// Tracks rate-limit check/rejection counts, broken down per `db_name`.
+#[derive(Default)]
+struct RateLimiterMetrics {
+    total_checks: AtomicU64,
+    total_rejections: AtomicU64,
+    per_db: DashMap<&'static str, DbMetrics>,
+}
+
+#[derive(Default)]
+struct DbMetrics {
+    rejections: AtomicU64,
+    throttled_users: Mutex<Hll>,
+}
+
+impl RateLimiterMetrics {
+    fn record_check(&self) {
+        self.total_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a rejection for `db_name`, folding `user_id` into that limit's
+    /// approximate distinct-user estimate.
+    fn record_rejection(&self, db_name: &'static str, user_id: UserId) {
+        self.total_rejections.fetch_add(1, Ordering::Relaxed);
+
+        let db_metrics = self.per_db.entry(db_name).or_default();
+        db_metrics.rejections.fetch_add(1, Ordering::Relaxed);
+        db_metrics.throttled_users.lock().insert(hash_user_id(user_id));
+    }
+
+    fn snapshot(&self) -> RateLimiterMetricsSnapshot {
+        RateLimiterMetricsSnapshot {
+            total_checks: self.total_checks.load(Ordering::Relaxed),
+            total_rejections: self.total_rejections.load(Ordering::Relaxed),
+            per_db: self
+                .per_db
+                .iter()
+                .map(|entry| DbMetricsSnapshot {
+                    db_name: *entry.key(),
+                    rejections: entry.rejections.load(Ordering::Relaxed),
+                    estimated_distinct_throttled_users: entry.throttled_users.lock().estimate(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn hash_user_id(user_id: UserId) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    user_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A point-in-time read of `RateLimiterMetrics`, suitable for scraping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimiterMetricsSnapshot {
+    pub total_checks: u64,
+    pub total_rejections: u64,
+    pub per_db: Vec<DbMetricsSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbMetricsSnapshot {
+    pub db_name: &'static str,
+    pub rejections: u64,
+    pub estimated_distinct_throttled_users: u64,
+}
+
+/// A HyperLogLog register set, used to estimate the number of distinct users
+/// throttled for a given limit without storing every user id we've ever rejected.
+struct Hll {
+    registers: Vec<u8>,
+}
+
+impl Default for Hll {
+    fn default() -> Self {
+        Hll {
+            registers: vec![0; HLL_NUM_REGISTERS],
+        }
+    }
+}
+
+impl Hll {
+    /// Hashes `hash` into a register, keeping the position of the leading one-bit of
+    /// the remaining bits if it's larger than what that register has seen before.
+    fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        let remaining_bits = hash.wrapping_shl(HLL_PRECISION);
+        let rho = (remaining_bits.leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+        let register = &mut self.registers[index];
+        *register = (*register).max(rho);
+    }
+
+    /// Estimates the number of distinct values inserted, via the standard HLL
+    /// harmonic-mean estimator with small-range linear-counting correction.
+    fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha_m = 0.7213 / (1.0 + 1.079 / m);
+        let raw_estimate = alpha_m * m * m
+            / self
+                .registers
+                .iter()
+                .map(|&register| 2f64.powi(-(register as i32)))
+                .sum::<f64>();
+
+        let estimate = if raw_estimate <= 2.5 * m {
+            let zero_registers = self.registers.iter().filter(|&&r| r == 0).count();
+            if zero_registers > 0 {
+                m * (m / zero_registers as f64).ln()
+            } else {
+                raw_estimate
+            }
+        } else {
+            raw_estimate
+        };
+
+        estimate.round().max(0.0) as u64
+    }
+}
+
+/// The outcome of a single bucket check.
+enum AllowResult {
+    Allowed,
+    /// The check was denied. `retry_after` is how long the caller should wait before
+    /// the slowest-to-refill exhausted bucket will have budget again.
+    Throttled { retry_after: StdDuration },
+}
+
+/// Returned by `RateLimiter::check` and `check_with_cost` when a user has exceeded
+/// their rate limit, along with how long they should wait before retrying.
+#[derive(ThisError, Debug)]
+#[error("rate limit exceeded, retry after {retry_after:?}")]
+pub struct RateLimitExceeded {
+    pub retry_after: StdDuration,
+}
+
+/// Holds up to two independent token buckets: one for request counts (`Ops`), always
+/// present, and one for byte/volume budgets (`Bytes`), present only for limits that
+/// declare `bytes_capacity`. A check only succeeds if every bucket it consumes from
+/// has enough budget.
 struct RateBucket {
+    ops: TokenBucket,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateBucket {
+    fn new(
+        capacity: usize,
+        refill_duration: Duration,
+        bytes_capacity: Option<usize>,
+        bytes_refill_duration: Option<Duration>,
+        now: DateTimeUtc,
+    ) -> Self {
+        RateBucket {
+            ops: TokenBucket::new(capacity, refill_duration, now),
+            bytes: bytes_capacity.map(|capacity| {
+                TokenBucket::new(
+                    capacity,
+                    bytes_refill_duration
+                        .expect("bytes_refill_duration must be set when bytes_capacity is"),
+                    now,
+                )
+            }),
+        }
+    }
+
+    /// Refills both buckets to `now` and, only if every relevant bucket has budget,
+    /// consumes `ops` `Ops` tokens and `bytes` `Bytes` tokens. If either bucket would
+    /// be left short, neither is consumed and `Throttled` reports how long until the
+    /// slowest-to-refill bucket has enough budget.
+    fn allow(&mut self, now: DateTimeUtc, ops: usize, bytes: usize) -> AllowResult {
+        self.ops.refill(now);
+        if let Some(bytes_bucket) = self.bytes.as_mut() {
+            bytes_bucket.refill(now);
+        }
+
+        let ops_available = self.ops.token_count >= ops;
+        let bytes_available = self
+            .bytes
+            .as_ref()
+            .map_or(true, |bucket| bucket.token_count >= bytes);
+
+        if ops_available && bytes_available {
+            self.ops.token_count -= ops;
+            if let Some(bytes_bucket) = self.bytes.as_mut() {
+                bytes_bucket.token_count -= bytes;
+            }
+            return AllowResult::Allowed;
+        }
+
+        let mut retry_after = StdDuration::ZERO;
+        if !ops_available {
+            retry_after = retry_after.max(self.ops.retry_after(now, ops));
+        }
+        if !bytes_available {
+            if let Some(bytes_bucket) = self.bytes.as_ref() {
+                retry_after = retry_after.max(bytes_bucket.retry_after(now, bytes));
+            }
+        }
+        AllowResult::Throttled { retry_after }
+    }
+}
+
+struct TokenBucket {
     capacity: usize,
     token_count: usize,
     refill_time_per_token: Duration,
     last_refill: DateTimeUtc,
 }
 
-impl RateBucket {
+impl TokenBucket {
     fn new(capacity: usize, refill_duration: Duration, now: DateTimeUtc) -> Self {
-        RateBucket {
+        TokenBucket {
             capacity,
             token_count: capacity,
             refill_time_per_token: refill_duration / capacity as i32,
@@ -122,16 +500,6 @@ impl RateBucket {
         }
     }
 
-    fn allow(&mut self, now: DateTimeUtc) -> bool {
-        self.refill(now);
-        if self.token_count > 0 {
-            self.token_count -= 1;
-            true
-        } else {
-            false
-        }
-    }
-
     fn refill(&mut self, now: DateTimeUtc) {
         let elapsed = now - self.last_refill;
         if elapsed >= self.refill_time_per_token {
@@ -142,6 +510,19 @@ impl RateBucket {
             self.last_refill = now;
         }
     }
+
+    /// How long until this bucket has accumulated at least `needed` tokens, assuming
+    /// it has already been refilled to `now`. Zero if it already has enough.
+    fn retry_after(&self, now: DateTimeUtc, needed: usize) -> StdDuration {
+        let deficit = needed.saturating_sub(self.token_count);
+        if deficit == 0 {
+            return StdDuration::ZERO;
+        }
+
+        let elapsed_since_refill = now - self.last_refill;
+        let wait = self.refill_time_per_token * deficit as i32 - elapsed_since_refill;
+        wait.to_std().unwrap_or(StdDuration::ZERO)
+    }
 }
 
 #[cfg(test)]
@@ -150,6 +531,65 @@ mod tests {
     use crate::db::{NewUserParams, TestDb};
     use gpui::TestAppContext;
 
+    #[test]
+    fn test_hll_cardinality_estimate() {
+        let mut hll = Hll::default();
+        let true_count = 100_000;
+        for user_id in 0..true_count {
+            hll.insert(hash_user_id(UserId::from_proto(user_id)));
+        }
+
+        let estimate = hll.estimate() as f64;
+        let error = (estimate - true_count as f64).abs() / true_count as f64;
+        assert!(
+            error < 0.05,
+            "estimate {estimate} too far from true count {true_count} (error {error})"
+        );
+    }
+
+    #[gpui::test]
+    async fn test_rate_limiter_metrics(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let test_db = TestDb::sqlite(cx.executor().clone());
+        let db = test_db.db().clone();
+        let user_1 = db
+            .create_user(
+                "user-1@zed.dev",
+                false,
+                NewUserParams {
+                    github_login: "user-1".into(),
+                    github_user_id: 1,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        let now = Utc::now();
+        let rate_limiter = RateLimiter::new(db.clone(), executor);
+
+        rate_limiter
+            .check_internal::<RateLimitA>(user_1, now)
+            .await
+            .unwrap();
+        rate_limiter
+            .check_internal::<RateLimitA>(user_1, now)
+            .await
+            .unwrap();
+        rate_limiter
+            .check_internal::<RateLimitA>(user_1, now)
+            .await
+            .unwrap_err();
+
+        let snapshot = rate_limiter.metrics_snapshot();
+        assert_eq!(snapshot.total_checks, 3);
+        assert_eq!(snapshot.total_rejections, 1);
+        assert_eq!(snapshot.per_db.len(), 1);
+        assert_eq!(snapshot.per_db[0].db_name, RateLimitA::db_name());
+        assert_eq!(snapshot.per_db[0].rejections, 1);
+        assert_eq!(snapshot.per_db[0].estimated_distinct_throttled_users, 1);
+    }
+
     #[gpui::test]
     async fn test_rate_limiter(cx: &mut TestAppContext) {
         let executor = Executor::Deterministic(cx.executor());
@@ -220,7 +660,7 @@ mod tests {
             .unwrap_err();
 
         // Ensure pending saves to the database are flushed.
-        cx.run_until_parked();
+        rate_limiter.flush_pending_saves().await;
 
         // Rate limits are reloaded from the database, so user A is still rate-limited
         // for resource A.
@@ -231,6 +671,283 @@ mod tests {
             .unwrap_err();
     }
 
+    #[gpui::test]
+    async fn test_rate_limiter_bytes_bucket(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let test_db = TestDb::sqlite(cx.executor().clone());
+        let db = test_db.db().clone();
+        let user_1 = db
+            .create_user(
+                "user-1@zed.dev",
+                false,
+                NewUserParams {
+                    github_login: "user-1".into(),
+                    github_user_id: 1,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        let now = Utc::now();
+        let rate_limiter = RateLimiter::new(db.clone(), executor);
+
+        // User 1 has plenty of request budget, but only 100 bytes of volume budget,
+        // so a 60-byte request followed by another 60-byte request is throttled even
+        // though the ops bucket still has tokens left.
+        rate_limiter
+            .check_internal_with_cost::<RateLimitWithBytes>(user_1, 1, 60, now)
+            .await
+            .unwrap();
+        rate_limiter
+            .check_internal_with_cost::<RateLimitWithBytes>(user_1, 1, 60, now)
+            .await
+            .unwrap_err();
+    }
+
+    #[gpui::test]
+    async fn test_rate_limiter_bytes_bucket_persistence(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let test_db = TestDb::sqlite(cx.executor().clone());
+        let db = test_db.db().clone();
+        let user_1 = db
+            .create_user(
+                "user-1@zed.dev",
+                false,
+                NewUserParams {
+                    github_login: "user-1".into(),
+                    github_user_id: 1,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        let now = Utc::now();
+        let rate_limiter = RateLimiter::new(db.clone(), executor.clone());
+
+        // Spend 60 of user 1's 100 bytes of volume budget. Plenty of ops budget
+        // remains, so only the bytes bucket is left partially drained.
+        rate_limiter
+            .check_internal_with_cost::<RateLimitWithBytes>(user_1, 1, 60, now)
+            .await
+            .unwrap();
+
+        // Ensure pending saves to the database are flushed.
+        rate_limiter.flush_pending_saves().await;
+
+        // Rate limits are reloaded from the database, so the bytes bucket should
+        // still reflect the 60 bytes already spent, not reset to full: another
+        // 60-byte request should be throttled rather than allowed.
+        let rate_limiter = RateLimiter::new(db.clone(), executor);
+        rate_limiter
+            .check_internal_with_cost::<RateLimitWithBytes>(user_1, 1, 60, now)
+            .await
+            .unwrap_err();
+    }
+
+    #[gpui::test]
+    async fn test_rate_limiter_bytes_bucket_independent_refill(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let test_db = TestDb::sqlite(cx.executor().clone());
+        let db = test_db.db().clone();
+        let user_1 = db
+            .create_user(
+                "user-1@zed.dev",
+                false,
+                NewUserParams {
+                    github_login: "user-1".into(),
+                    github_user_id: 1,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        let now = Utc::now();
+        let rate_limiter = RateLimiter::new(db.clone(), executor.clone());
+
+        // RateLimitMixedRefill's ops bucket ticks once per second, its bytes bucket
+        // once per 20ms. Spend 1 op and 60 bytes now, then spend another op 500ms
+        // later without touching bytes. That 500ms isn't enough to refill the ops
+        // bucket (so its `last_refill` doesn't move), but it is enough to refill the
+        // bytes bucket many times over (so its `last_refill` does) — the two buckets'
+        // `last_refill` values diverge.
+        rate_limiter
+            .check_internal_with_cost::<RateLimitMixedRefill>(user_1, 1, 60, now)
+            .await
+            .unwrap();
+        let now = now + Duration::milliseconds(500);
+        rate_limiter
+            .check_internal_with_cost::<RateLimitMixedRefill>(user_1, 1, 0, now)
+            .await
+            .unwrap();
+
+        rate_limiter.flush_pending_saves().await;
+
+        // Between the two checks, 500ms of real refill legitimately grew the bytes
+        // bucket from 40 back up to 65 (25 tokens at 20ms/token). Reload at the exact
+        // same instant as the last check: if the bytes bucket's `last_refill` were
+        // conflated with the ops bucket's stale (pre-divergence) timestamp, reloading
+        // would see a large fake additional elapsed time and over-credit tokens it
+        // already credited once. With independent timestamps, zero time has passed
+        // since the last real bytes refill, so the budget should read as exactly the
+        // 65 tokens persisted — no further ops cost this time so the ops bucket
+        // doesn't gate the check.
+        let rate_limiter = RateLimiter::new(db.clone(), executor);
+        rate_limiter
+            .check_internal_with_cost::<RateLimitMixedRefill>(user_1, 0, 66, now)
+            .await
+            .unwrap_err();
+        rate_limiter
+            .check_internal_with_cost::<RateLimitMixedRefill>(user_1, 0, 65, now)
+            .await
+            .unwrap();
+    }
+
+    #[gpui::test]
+    async fn test_rate_limiter_retry_after(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let test_db = TestDb::sqlite(cx.executor().clone());
+        let db = test_db.db().clone();
+        let user_1 = db
+            .create_user(
+                "user-1@zed.dev",
+                false,
+                NewUserParams {
+                    github_login: "user-1".into(),
+                    github_user_id: 1,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        let mut now = Utc::now();
+        let rate_limiter = RateLimiter::new(db.clone(), executor);
+
+        // Drain user 1's bucket (capacity 2, refilling one token per second).
+        rate_limiter
+            .check_internal::<RateLimitA>(user_1, now)
+            .await
+            .unwrap();
+        rate_limiter
+            .check_internal::<RateLimitA>(user_1, now)
+            .await
+            .unwrap();
+
+        let err = rate_limiter
+            .check_internal::<RateLimitA>(user_1, now)
+            .await
+            .unwrap_err();
+        assert_eq!(err.retry_after, StdDuration::from_secs(1));
+
+        // As real time passes, the reported wait shrinks accordingly.
+        now += Duration::milliseconds(400);
+        let err = rate_limiter
+            .check_internal::<RateLimitA>(user_1, now)
+            .await
+            .unwrap_err();
+        assert_eq!(err.retry_after, StdDuration::from_millis(600));
+    }
+
+    #[gpui::test]
+    async fn test_rate_limiter_concurrent_cold_cache(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let test_db = TestDb::sqlite(cx.executor().clone());
+        let db = test_db.db().clone();
+        let user_1 = db
+            .create_user(
+                "user-1@zed.dev",
+                false,
+                NewUserParams {
+                    github_login: "user-1".into(),
+                    github_user_id: 1,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        let now = Utc::now();
+        let rate_limiter = RateLimiter::new(db.clone(), executor);
+
+        // Fire many concurrent checks against a cold cache for the same user and
+        // limit. If the load-or-init path weren't race-free, concurrent misses could
+        // each load (or create) their own pristine bucket and clobber each other's
+        // decrements, letting more than `capacity` requests through.
+        let results = futures::future::join_all(
+            (0..10).map(|_| rate_limiter.check_internal::<RateLimitA>(user_1, now)),
+        )
+        .await;
+
+        let allowed = results.iter().filter(|result| result.is_ok()).count();
+        assert_eq!(allowed, RateLimitA::capacity());
+    }
+
+    #[gpui::test]
+    async fn test_rate_limiter_batched_persistence(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let test_db = TestDb::sqlite(cx.executor().clone());
+        let db = test_db.db().clone();
+        let user_1 = db
+            .create_user(
+                "user-1@zed.dev",
+                false,
+                NewUserParams {
+                    github_login: "user-1".into(),
+                    github_user_id: 1,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        let now = Utc::now();
+        let flush_interval = StdDuration::from_secs(30);
+        let rate_limiter =
+            RateLimiter::new_with_save_flush_interval(db.clone(), executor, flush_interval);
+
+        // Many checks for the same key within one flush interval...
+        for _ in 0..5 {
+            rate_limiter
+                .check_internal::<RateLimitB>(user_1, now)
+                .await
+                .unwrap();
+        }
+
+        // ...leave the database untouched until the buffer is flushed...
+        cx.run_until_parked();
+        assert!(db
+            .get_rate_bucket(user_1, RateLimitB::db_name())
+            .await
+            .unwrap()
+            .is_none());
+
+        // ...at which point only the latest state is written, in a single write.
+        cx.executor().advance_clock(flush_interval);
+        cx.run_until_parked();
+        let saved = db
+            .get_rate_bucket(user_1, RateLimitB::db_name())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.token_count, RateLimitB::capacity() as i32 - 5);
+
+        // The persisted state is still correct after a restart.
+        let rate_limiter = RateLimiter::new(db.clone(), Executor::Deterministic(cx.executor()));
+        for _ in 0..(RateLimitB::capacity() - 5) {
+            rate_limiter
+                .check_internal::<RateLimitB>(user_1, now)
+                .await
+                .unwrap();
+        }
+        rate_limiter
+            .check_internal::<RateLimitB>(user_1, now)
+            .await
+            .unwrap_err();
+    }
+
     struct RateLimitA;
 
     impl RateLimit for RateLimitA {
@@ -262,4 +979,52 @@ mod tests {
             "rate-limit-b"
         }
     }
-}
\ No newline at end of file
+
+    struct RateLimitWithBytes;
+
+    impl RateLimit for RateLimitWithBytes {
+        fn capacity() -> usize {
+            100
+        }
+
+        fn refill_duration() -> Duration {
+            Duration::seconds(2)
+        }
+
+        fn db_name() -> &'static str {
+            "rate-limit-with-bytes"
+        }
+
+        fn bytes_capacity() -> Option<usize> {
+            Some(100)
+        }
+
+        fn bytes_refill_duration() -> Option<Duration> {
+            Some(Duration::seconds(2))
+        }
+    }
+
+    struct RateLimitMixedRefill;
+
+    impl RateLimit for RateLimitMixedRefill {
+        fn capacity() -> usize {
+            2
+        }
+
+        fn refill_duration() -> Duration {
+            Duration::seconds(2)
+        }
+
+        fn db_name() -> &'static str {
+            "rate-limit-mixed-refill"
+        }
+
+        fn bytes_capacity() -> Option<usize> {
+            Some(100)
+        }
+
+        fn bytes_refill_duration() -> Option<Duration> {
+            Some(Duration::seconds(2))
+        }
+    }
+}